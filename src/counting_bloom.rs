@@ -1,26 +1,42 @@
-use murmur3::murmur3_x64_128;
 use std::marker::PhantomData;
 use std::io::Cursor;
-use byteorder::{BigEndian, ByteOrder};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use hashes::Hashes;
+use hashes::{BloomHashIndex, Mode};
 use packed_vec::PackedVec;
+use DecodeError;
 
 // http://pages.cs.wisc.edu/~jussara/papers/00ton.pdf
 pub struct CountingBloom<K> {
     packed_vec: PackedVec,
     num_hashes: usize,
+    num_nonzero: usize,
+    mode: Mode,
+    tweak: u32,
     _marker: PhantomData<K>,
 }
 
-impl<K: AsRef<[u8]>> CountingBloom<K> {
+impl<K: BloomHashIndex> CountingBloom<K> {
     pub fn new(num_items: u64, max_false_prob: f64) -> CountingBloom<K> {
+        Self::with_mode(num_items, max_false_prob, Mode::DoubleHashing, 0)
+    }
+
+    /// Like `new`, but lets callers pick the hashing `mode` and a `tweak`
+    /// seed for `Mode::PerHashSeed`, decorrelating the counters of
+    /// `CountingBloom`s that are meant to be used independently. Filters you
+    /// intend to later `union`/`saturating_add` must be built with the same
+    /// `mode` and `tweak`; `union` and `saturating_add` panic when either
+    /// differs.
+    pub fn with_mode(num_items: u64, max_false_prob: f64, mode: Mode, tweak: u32) -> CountingBloom<K> {
         assert!(max_false_prob > 0.0 && max_false_prob < 1.0, "False positive probability must be in interval (0, 1)");
         let num_bits = Self::optimal_num_bits(num_items, max_false_prob);
         let num_hashes = Self::optimal_num_hashes(num_bits, num_items);
         CountingBloom {
             packed_vec: PackedVec::new(num_bits, 4),
             num_hashes: num_hashes,
+            num_nonzero: 0,
+            mode: mode,
+            tweak: tweak,
             _marker: PhantomData,
         }
     }
@@ -37,48 +53,185 @@ impl<K: AsRef<[u8]>> CountingBloom<K> {
         num_hashes.round() as usize
     }
 
-    fn key_hashes(key: K) -> Hashes {
-        let mut hash_result = [0u8; 16];
-        let mut key_reader = Cursor::new(key);
-        murmur3_x64_128(&mut key_reader, 0, &mut hash_result);
-
-        let hash1 = BigEndian::read_u64(&hash_result);
-        let hash2 = BigEndian::read_u64(&hash_result[4..]);
-
-        Hashes::new(hash1, hash2)
+    // Computed once per `add`/`remove`/`contains` call rather than per bit
+    // index, so the blanket `AsRef<[u8]>` impl only hashes a key's bytes once
+    // under `Mode::DoubleHashing`.
+    fn hash_indices(&self, key: &K) -> Vec<u64> {
+        match self.mode {
+            Mode::DoubleHashing => key.hash_indices(self.num_hashes as u64),
+            Mode::PerHashSeed => {
+                (0..self.num_hashes as u64).map(|index| key.hash_at_index_seeded(index, self.tweak)).collect()
+            }
+        }
     }
 
     pub fn add(&mut self, key: K) {
-        for hash in Self::key_hashes(key).take(self.num_hashes) {
-            let count = self.packed_vec.len();
-            self.packed_vec.increment(hash as usize % count);
+        let count = self.packed_vec.len();
+        for hash in self.hash_indices(&key) {
+            if self.packed_vec.increment(hash as usize % count) {
+                self.num_nonzero += 1;
+            }
         }
     }
 
     pub fn remove(&mut self, key: K) {
-        for hash in Self::key_hashes(key).take(self.num_hashes) {
-            let count = self.packed_vec.len();
-            self.packed_vec.decrement(hash as usize % count);
+        let count = self.packed_vec.len();
+        for hash in self.hash_indices(&key) {
+            if self.packed_vec.decrement(hash as usize % count) {
+                self.num_nonzero -= 1;
+            }
         }
     }
 
     pub fn contains(&mut self, key: K) -> bool {
         let mut contains_key = true;
-        for hash in Self::key_hashes(key).take(self.num_hashes) {
-            let count = self.packed_vec.len();
+        let count = self.packed_vec.len();
+        for hash in self.hash_indices(&key) {
             contains_key &= self.packed_vec.get(hash as usize % count).unwrap() > 0;
         }
         contains_key
     }
 }
 
+impl<K> CountingBloom<K> {
+    /// Encodes this filter as a self-describing byte buffer: a header of
+    /// `num_hashes`, the element count, `element_size_bits`, the hashing mode
+    /// and tweak, followed by the raw `PackedVec` storage.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u64::<BigEndian>(self.num_hashes as u64).unwrap();
+        buf.write_u64::<BigEndian>(self.packed_vec.len() as u64).unwrap();
+        buf.write_u64::<BigEndian>(self.packed_vec.element_size_bits() as u64).unwrap();
+        buf.write_u8(self.mode.to_u8()).unwrap();
+        buf.write_u32::<BigEndian>(self.tweak).unwrap();
+        buf.extend_from_slice(&self.packed_vec.to_raw_bytes());
+        buf
+    }
+
+    /// Decodes a filter previously produced by `to_bytes`, validating that the
+    /// decoded element count matches the backing buffer before returning it.
+    pub fn from_bytes(bytes: &[u8]) -> Result<CountingBloom<K>, DecodeError> {
+        let mut cursor = Cursor::new(bytes);
+        let num_hashes = cursor.read_u64::<BigEndian>()? as usize;
+        let num_elem = cursor.read_u64::<BigEndian>()? as usize;
+        let element_size_bits = cursor.read_u64::<BigEndian>()? as usize;
+        let mode_byte = cursor.read_u8()?;
+        let mode = Mode::from_u8(mode_byte).ok_or(DecodeError::InvalidMode(mode_byte))?;
+        let tweak = cursor.read_u32::<BigEndian>()?;
+        let data_start = cursor.position() as usize;
+        let data_bytes = &bytes[data_start..];
+        if data_bytes.len() % 8 != 0 {
+            return Err(DecodeError::LengthMismatch);
+        }
+        let actual_buckets = data_bytes.len() / 8;
+        if actual_buckets != PackedVec::expected_bucket_count(num_elem, element_size_bits) {
+            return Err(DecodeError::LengthMismatch);
+        }
+        let mut data_cursor = Cursor::new(data_bytes);
+        let mut buckets = Vec::with_capacity(actual_buckets);
+        for _ in 0..actual_buckets {
+            buckets.push(data_cursor.read_u64::<BigEndian>()? as usize);
+        }
+        let mut packed_vec = PackedVec::from_raw_buckets(num_elem, element_size_bits, buckets);
+        let num_nonzero = Self::count_nonzero(&mut packed_vec);
+        Ok(CountingBloom {
+            packed_vec: packed_vec,
+            num_hashes: num_hashes,
+            num_nonzero: num_nonzero,
+            mode: mode,
+            tweak: tweak,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Fraction of counters currently non-zero, in `[0, 1]`.
+    pub fn fill_ratio(&self) -> f64 {
+        self.num_nonzero as f64 / self.packed_vec.len() as f64
+    }
+
+    /// Estimated false-positive probability given the fraction of non-zero
+    /// counters `X/m`: `(X/m)^k`. Behaves like `Bloom`'s estimate since a
+    /// lookup only needs to know whether a counter is zero, not its value.
+    pub fn estimated_false_positive_rate(&self) -> f64 {
+        self.fill_ratio().powi(self.num_hashes as i32)
+    }
+
+    /// Estimated number of distinct items currently held, back-solved from
+    /// the fraction of non-zero counters: `n ≈ -(m/k) * ln(1 - X/m)`. This
+    /// counts distinct keys added and not yet fully `remove`d, not the sum
+    /// of counter values.
+    pub fn estimated_len(&self) -> f64 {
+        let m = self.packed_vec.len() as f64;
+        let k = self.num_hashes as f64;
+        -(m / k) * (1.0 - self.fill_ratio()).ln()
+    }
+
+    /// Element-wise maxes `other`'s counters into this filter, keeping
+    /// whichever counter at each index represents the stronger membership
+    /// signal. Both filters must share the same `num_hashes`, element count
+    /// and `element_size_bits`.
+    pub fn union(&mut self, other: &mut CountingBloom<K>) {
+        self.assert_compatible(other);
+        self.packed_vec.merge_max(&mut other.packed_vec);
+        self.num_nonzero = Self::count_nonzero(&mut self.packed_vec);
+    }
+
+    /// Element-wise saturating-adds `other`'s counters into this filter,
+    /// recovering the true combined multiplicities when two filters were
+    /// built independently over disjoint shards of the same key set. Both
+    /// filters must share the same `num_hashes`, element count and
+    /// `element_size_bits`.
+    pub fn saturating_add(&mut self, other: &mut CountingBloom<K>) {
+        self.assert_compatible(other);
+        self.packed_vec.merge_saturating_add(&mut other.packed_vec);
+        self.num_nonzero = Self::count_nonzero(&mut self.packed_vec);
+    }
+
+    fn assert_compatible(&self, other: &CountingBloom<K>) {
+        assert_eq!(self.num_hashes, other.num_hashes, "Cannot merge filters with a different num_hashes");
+        assert_eq!(self.packed_vec.len(), other.packed_vec.len(), "Cannot merge filters with a different element count");
+        assert_eq!(self.packed_vec.element_size_bits(), other.packed_vec.element_size_bits(), "Cannot merge filters with a different element_size_bits");
+        assert_eq!(self.mode, other.mode, "Cannot merge filters with a different hashing mode");
+        assert_eq!(self.tweak, other.tweak, "Cannot merge filters with a different tweak");
+    }
+
+    fn count_nonzero(packed_vec: &mut PackedVec) -> usize {
+        let mut count = 0;
+        for i in 0..packed_vec.len() {
+            if packed_vec.get(i).unwrap() > 0 {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K> ::serde::Serialize for CountingBloom<K> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: ::serde::Serializer
+    {
+        serializer.serialize_bytes(&self.to_bytes())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K> ::serde::Deserialize<'de> for CountingBloom<K> {
+    fn deserialize<D>(deserializer: D) -> Result<CountingBloom<K>, D::Error>
+        where D: ::serde::Deserializer<'de>
+    {
+        let bytes: Vec<u8> = ::serde::Deserialize::deserialize(deserializer)?;
+        CountingBloom::from_bytes(&bytes).map_err(::serde::de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate test;
 
     use self::test::{Bencher, black_box};
 
-    use super::CountingBloom;
+    use super::{CountingBloom, Mode, PackedVec};
     use hamcrest::*;
 
     #[test]
@@ -89,6 +242,103 @@ mod tests {
         assert_that(bloom.contains("b"), is(equal_to(false)));
     }
 
+    #[test]
+    fn per_hash_seed_mode_also_supports_add_and_remove() {
+        let mut bloom: CountingBloom<&'static str> = CountingBloom::with_mode(10_000, 0.01, Mode::PerHashSeed, 42);
+        bloom.add("a");
+        assert_that(bloom.contains("a"), is(equal_to(true)));
+        assert_that(bloom.contains("b"), is(equal_to(false)));
+        bloom.remove("a");
+        assert_that(bloom.contains("a"), is(equal_to(false)));
+    }
+
+    #[test]
+    fn fill_ratio_tracks_nonzero_counters() {
+        let mut bloom: CountingBloom<&'static str> = CountingBloom::new(10_000, 0.01);
+        assert_that(bloom.fill_ratio(), is(equal_to(0.0)));
+        bloom.add("a");
+        assert_that(bloom.fill_ratio() > 0.0, is(equal_to(true)));
+        bloom.remove("a");
+        assert_that(bloom.fill_ratio(), is(equal_to(0.0)));
+    }
+
+    #[test]
+    fn union_contains_keys_from_either_filter() {
+        let mut a: CountingBloom<&'static str> = CountingBloom::new(10_000, 0.01);
+        let mut b: CountingBloom<&'static str> = CountingBloom::new(10_000, 0.01);
+        a.add("a");
+        b.add("b");
+        a.union(&mut b);
+        assert_that(a.contains("a"), is(equal_to(true)));
+        assert_that(a.contains("b"), is(equal_to(true)));
+    }
+
+    #[test]
+    fn saturating_add_recovers_counts_from_disjoint_shards() {
+        let mut a: CountingBloom<&'static str> = CountingBloom::new(10_000, 0.01);
+        let mut b: CountingBloom<&'static str> = CountingBloom::new(10_000, 0.01);
+        a.add("a");
+        b.add("a");
+        a.saturating_add(&mut b);
+        assert_that(a.contains("a"), is(equal_to(true)));
+        a.remove("a");
+        assert_that(a.contains("a"), is(equal_to(true)));
+        a.remove("a");
+        assert_that(a.contains("a"), is(equal_to(false)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn union_panics_for_incompatible_filters() {
+        let mut a: CountingBloom<&'static str> = CountingBloom::new(10_000, 0.01);
+        let mut b: CountingBloom<&'static str> = CountingBloom::new(100, 0.01);
+        a.union(&mut b);
+    }
+
+    #[test]
+    #[should_panic]
+    fn union_panics_for_filters_with_a_different_element_size() {
+        let mut a: CountingBloom<&'static str> = CountingBloom::new(10_000, 0.01);
+        let mut b = CountingBloom {
+            packed_vec: PackedVec::new(a.packed_vec.len(), 2),
+            ..CountingBloom::new(10_000, 0.01)
+        };
+        a.union(&mut b);
+    }
+
+    #[test]
+    #[should_panic]
+    fn union_panics_for_filters_with_a_different_mode_or_tweak() {
+        let mut a: CountingBloom<&'static str> = CountingBloom::with_mode(10_000, 0.01, Mode::DoubleHashing, 0);
+        let mut b: CountingBloom<&'static str> = CountingBloom::with_mode(10_000, 0.01, Mode::PerHashSeed, 42);
+        a.union(&mut b);
+    }
+
+    #[test]
+    fn filter_survives_a_round_trip_through_bytes() {
+        let mut bloom: CountingBloom<&'static str> = CountingBloom::new(10_000, 0.01);
+        bloom.add("a");
+        bloom.add("a");
+        let mut decoded: CountingBloom<&'static str> = CountingBloom::from_bytes(&bloom.to_bytes()).unwrap();
+        assert_that(decoded.contains("a"), is(equal_to(true)));
+        assert_that(decoded.contains("b"), is(equal_to(false)));
+        // Counters round-trip too, not just their zero/non-zero state: "a"
+        // was added twice, so it should still be considered present after
+        // a single `remove`.
+        decoded.remove("a");
+        assert_that(decoded.contains("a"), is(equal_to(true)));
+        decoded.remove("a");
+        assert_that(decoded.contains("a"), is(equal_to(false)));
+    }
+
+    #[test]
+    fn decoding_stops_at_a_truncated_buffer() {
+        let bloom: CountingBloom<&'static str> = CountingBloom::new(10_000, 0.01);
+        let mut bytes = bloom.to_bytes();
+        bytes.truncate(4);
+        assert_that(CountingBloom::<&'static str>::from_bytes(&bytes).is_err(), is(equal_to(true)));
+    }
+
     #[bench]
     fn creation_overhead(b: &mut Bencher) {
         b.iter(|| {
@@ -1,3 +1,8 @@
+use std::io::Cursor;
+
+use murmur3::murmur3_x64_128;
+use byteorder::{BigEndian, ByteOrder};
+
 // https://www.eecs.harvard.edu/~michaelm/postscripts/tr-02-05.pdf
 pub struct Hashes {
     base: u64,
@@ -22,3 +27,92 @@ impl Iterator for Hashes {
         Some(next_value)
     }
 }
+
+/// Selects how a filter derives its bit positions from a key's
+/// `BloomHashIndex` implementation.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// Kirsch-Mitzenmacher double hashing from a single hash call.
+    DoubleHashing,
+    /// BIP37-style independent hashing: each bit position comes from its own
+    /// hash, reseeded per index with BIP37's `index * 0xFBA4C795 + tweak`
+    /// seed. The hash is murmur3_x64_128 rather than BIP37's 32-bit murmur3,
+    /// so the bit positions do not match those of BIP37 filters. The
+    /// per-filter tweak lets multiple filters over the same data use
+    /// decorrelated bit patterns.
+    PerHashSeed,
+}
+
+impl Mode {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Mode::DoubleHashing => 0,
+            Mode::PerHashSeed => 1,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Mode> {
+        match value {
+            0 => Some(Mode::DoubleHashing),
+            1 => Some(Mode::PerHashSeed),
+            _ => None,
+        }
+    }
+}
+
+/// A source of `num_hashes` bit indices for a single key.
+///
+/// Implementing this directly lets callers supply domain-specific stable
+/// hashing (pre-hashed ids, keys that already carry entropy, ...) without
+/// forcing a conversion to a byte slice on every `add`/`contains`/`remove`.
+pub trait BloomHashIndex {
+    /// Returns the hash used to derive the bit position at `index` under
+    /// `Mode::DoubleHashing`. Each call is independent, so computing all of a
+    /// filter's `num_hashes` indices this way recomputes any underlying
+    /// digest from scratch every time; prefer `hash_indices` on that path.
+    fn hash_at_index(&self, index: u64) -> u64;
+
+    /// Returns the hash used to derive the bit position at `index` under
+    /// `Mode::PerHashSeed`, reseeded by `tweak`. Defaults to `hash_at_index`,
+    /// ignoring the tweak, for implementors with no reseedable hash of their
+    /// own.
+    fn hash_at_index_seeded(&self, index: u64, tweak: u32) -> u64 {
+        let _ = tweak;
+        self.hash_at_index(index)
+    }
+
+    /// Returns the hashes for indices `0..num_hashes` under
+    /// `Mode::DoubleHashing`. Defaults to calling `hash_at_index` per index;
+    /// implementors whose hash is backed by a single expensive digest (like
+    /// the blanket `AsRef<[u8]>` impl below) should override this to compute
+    /// that digest once and derive every index from it.
+    fn hash_indices(&self, num_hashes: u64) -> Vec<u64> {
+        (0..num_hashes).map(|index| self.hash_at_index(index)).collect()
+    }
+}
+
+impl<T: AsRef<[u8]>> BloomHashIndex for T {
+    fn hash_at_index(&self, index: u64) -> u64 {
+        self.hash_indices(index + 1)[index as usize]
+    }
+
+    fn hash_at_index_seeded(&self, index: u64, tweak: u32) -> u64 {
+        let seed = (index as u32).wrapping_mul(0xFBA4C795).wrapping_add(tweak);
+        let mut hash_result = [0u8; 16];
+        let mut key_reader = Cursor::new(self.as_ref());
+        murmur3_x64_128(&mut key_reader, seed, &mut hash_result);
+
+        BigEndian::read_u64(&hash_result)
+    }
+
+    fn hash_indices(&self, num_hashes: u64) -> Vec<u64> {
+        let mut hash_result = [0u8; 16];
+        let mut key_reader = Cursor::new(self.as_ref());
+        murmur3_x64_128(&mut key_reader, 0, &mut hash_result);
+
+        let hash1 = BigEndian::read_u64(&hash_result);
+        let hash2 = BigEndian::read_u64(&hash_result[4..]);
+
+        Hashes::new(hash1, hash2).take(num_hashes as usize).collect()
+    }
+}
@@ -3,35 +3,94 @@
 extern crate bit_vec;
 extern crate murmur3;
 extern crate byteorder;
+#[cfg(feature = "serde")]
+extern crate serde;
 
 #[cfg(test)]
 extern crate hamcrest;
+#[cfg(test)]
+extern crate rand;
 
 mod hashes;
+mod packed_vec;
+mod counting_bloom;
+mod log_bloom;
 
 use bit_vec::BitVec;
-use murmur3::murmur3_x64_128;
 use std::marker::PhantomData;
-use std::io::Cursor;
-use byteorder::{BigEndian, ByteOrder};
+use std::io::{self, Cursor};
+use std::fmt;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+pub use counting_bloom::CountingBloom;
+pub use hashes::{BloomHashIndex, Mode};
+pub use log_bloom::LogBloom;
+
+/// Error returned when a filter can't be reconstructed from its serialized
+/// byte representation.
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(io::Error),
+    LengthMismatch,
+    InvalidMode(u8),
+}
 
-use hashes::Hashes;
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Io(ref err) => write!(f, "failed to read filter bytes: {}", err),
+            DecodeError::LengthMismatch => write!(f, "decoded length does not match the backing buffer"),
+            DecodeError::InvalidMode(value) => write!(f, "{} is not a valid hashing mode", value),
+        }
+    }
+}
+
+impl ::std::error::Error for DecodeError {
+    fn description(&self) -> &str {
+        match *self {
+            DecodeError::Io(_) => "failed to read filter bytes",
+            DecodeError::LengthMismatch => "decoded length does not match the backing buffer",
+            DecodeError::InvalidMode(_) => "decoded byte is not a valid hashing mode",
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> DecodeError {
+        DecodeError::Io(err)
+    }
+}
 
 // http://dmod.eu/deca/ft_gateway.cfm.pdf
 pub struct Bloom<K> {
     bit_vec: BitVec,
     num_hashes: usize,
+    num_bits_set: usize,
+    mode: Mode,
+    tweak: u32,
     _marker: PhantomData<K>,
 }
 
-impl<K: AsRef<[u8]>> Bloom<K> {
+impl<K: BloomHashIndex> Bloom<K> {
     pub fn new(num_items: u64, max_false_prob: f64) -> Bloom<K> {
+        Self::with_mode(num_items, max_false_prob, Mode::DoubleHashing, 0)
+    }
+
+    /// Like `new`, but lets callers pick the hashing `mode` and a `tweak`
+    /// seed for `Mode::PerHashSeed`, decorrelating the bit patterns of
+    /// `Bloom`s that are meant to be used independently. Filters you intend
+    /// to later `union`/`intersect` must be built with the same `mode` and
+    /// `tweak`; `union` and `intersect` panic when either differs.
+    pub fn with_mode(num_items: u64, max_false_prob: f64, mode: Mode, tweak: u32) -> Bloom<K> {
         assert!(max_false_prob > 0.0 && max_false_prob < 1.0, "False positive probability must be in interval (0, 1)");
         let num_bits = Self::optimal_num_bits(num_items, max_false_prob);
         let num_hashes = Self::optimal_num_hashes(num_bits, num_items);
         Bloom {
             bit_vec: BitVec::from_elem(num_bits, false),
             num_hashes: num_hashes,
+            num_bits_set: 0,
+            mode: mode,
+            tweak: tweak,
             _marker: PhantomData,
         }
     }
@@ -48,41 +107,162 @@ impl<K: AsRef<[u8]>> Bloom<K> {
         num_hashes.round() as usize
     }
 
-    fn key_hashes(key: K) -> Hashes {
-        let mut hash_result = [0u8; 16];
-        let mut key_reader = Cursor::new(key);
-        murmur3_x64_128(&mut key_reader, 0, &mut hash_result);
-
-        let hash1 = BigEndian::read_u64(&hash_result);
-        let hash2 = BigEndian::read_u64(&hash_result[4..]);
-
-        Hashes::new(hash1, hash2)
+    // Computed once per `add`/`contains` call rather than per bit index, so
+    // the blanket `AsRef<[u8]>` impl only hashes a key's bytes once under
+    // `Mode::DoubleHashing`.
+    fn hash_indices(&self, key: &K) -> Vec<u64> {
+        match self.mode {
+            Mode::DoubleHashing => key.hash_indices(self.num_hashes as u64),
+            Mode::PerHashSeed => {
+                (0..self.num_hashes as u64).map(|index| key.hash_at_index_seeded(index, self.tweak)).collect()
+            }
+        }
     }
 
     pub fn add(&mut self, key: K) {
-        for hash in Self::key_hashes(key).take(self.num_hashes) {
-            let count = self.bit_vec.len();
-            self.bit_vec.set(hash as usize % count, true);
+        let count = self.bit_vec.len();
+        for hash in self.hash_indices(&key) {
+            let i = hash as usize % count;
+            if !self.bit_vec.get(i).unwrap() {
+                self.bit_vec.set(i, true);
+                self.num_bits_set += 1;
+            }
         }
     }
 
     pub fn contains(&self, key: K) -> bool {
         let mut contains_key = true;
-        for hash in Self::key_hashes(key).take(self.num_hashes) {
-            let count = self.bit_vec.len();
+        let count = self.bit_vec.len();
+        for hash in self.hash_indices(&key) {
             contains_key &= self.bit_vec.get(hash as usize % count).unwrap();
         }
         contains_key
     }
 }
 
+impl<K> Bloom<K> {
+    /// Encodes this filter as a self-describing byte buffer: a header of
+    /// `num_hashes`, the bit length, the hashing mode and tweak, followed by
+    /// the raw `BitVec` storage.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u64::<BigEndian>(self.num_hashes as u64).unwrap();
+        buf.write_u64::<BigEndian>(self.bit_vec.len() as u64).unwrap();
+        buf.write_u8(self.mode.to_u8()).unwrap();
+        buf.write_u32::<BigEndian>(self.tweak).unwrap();
+        buf.extend_from_slice(&self.bit_vec.to_bytes());
+        buf
+    }
+
+    /// Decodes a filter previously produced by `to_bytes`, validating that the
+    /// decoded bit length matches the backing buffer before returning it.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Bloom<K>, DecodeError> {
+        let mut cursor = Cursor::new(bytes);
+        let num_hashes = cursor.read_u64::<BigEndian>()? as usize;
+        let num_bits = cursor.read_u64::<BigEndian>()? as usize;
+        let mode_byte = cursor.read_u8()?;
+        let mode = Mode::from_u8(mode_byte).ok_or(DecodeError::InvalidMode(mode_byte))?;
+        let tweak = cursor.read_u32::<BigEndian>()?;
+        let data_start = cursor.position() as usize;
+        let data_bytes = &bytes[data_start..];
+        if num_bits == 0 || data_bytes.len() != (num_bits + 7) / 8 {
+            return Err(DecodeError::LengthMismatch);
+        }
+        let mut bit_vec = BitVec::from_bytes(data_bytes);
+        bit_vec.truncate(num_bits);
+        let num_bits_set = Self::count_bits_set(&bit_vec);
+        Ok(Bloom {
+            bit_vec: bit_vec,
+            num_hashes: num_hashes,
+            num_bits_set: num_bits_set,
+            mode: mode,
+            tweak: tweak,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Fraction of bits currently set, in `[0, 1]`.
+    pub fn fill_ratio(&self) -> f64 {
+        self.num_bits_set as f64 / self.bit_vec.len() as f64
+    }
+
+    /// Estimated false-positive probability given the observed fill ratio
+    /// `X/m`: `(X/m)^k`, which avoids needing a tracked item count `n`.
+    pub fn estimated_false_positive_rate(&self) -> f64 {
+        self.fill_ratio().powi(self.num_hashes as i32)
+    }
+
+    /// Estimated number of distinct items added, back-solved from the
+    /// observed fill ratio: `n ≈ -(m/k) * ln(1 - X/m)`.
+    pub fn estimated_len(&self) -> f64 {
+        let m = self.bit_vec.len() as f64;
+        let k = self.num_hashes as f64;
+        -(m / k) * (1.0 - self.fill_ratio()).ln()
+    }
+
+    /// Bitwise-ORs `other` into this filter in place, so it reports a key as
+    /// contained if either filter held it. Both filters must share the same
+    /// `num_hashes` and bit length, which holds for any two filters built
+    /// with the same `new(num_items, max_false_prob)` parameters.
+    pub fn union(&mut self, other: &Bloom<K>) {
+        self.assert_compatible(other);
+        self.bit_vec.union(&other.bit_vec);
+        self.num_bits_set = Self::count_bits_set(&self.bit_vec);
+    }
+
+    /// Bitwise-ANDs `other` into this filter in place, so it reports a key as
+    /// contained only if both filters held it. Both filters must share the
+    /// same `num_hashes` and bit length.
+    pub fn intersect(&mut self, other: &Bloom<K>) {
+        self.assert_compatible(other);
+        self.bit_vec.intersect(&other.bit_vec);
+        self.num_bits_set = Self::count_bits_set(&self.bit_vec);
+    }
+
+    fn assert_compatible(&self, other: &Bloom<K>) {
+        assert_eq!(self.num_hashes, other.num_hashes, "Cannot merge filters with a different num_hashes");
+        assert_eq!(self.bit_vec.len(), other.bit_vec.len(), "Cannot merge filters with a different bit length");
+        assert_eq!(self.mode, other.mode, "Cannot merge filters with a different hashing mode");
+        assert_eq!(self.tweak, other.tweak, "Cannot merge filters with a different tweak");
+    }
+
+    fn count_bits_set(bit_vec: &BitVec) -> usize {
+        let mut count = 0;
+        for bit in bit_vec.iter() {
+            if bit {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K> serde::Serialize for Bloom<K> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        serializer.serialize_bytes(&self.to_bytes())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K> serde::Deserialize<'de> for Bloom<K> {
+    fn deserialize<D>(deserializer: D) -> Result<Bloom<K>, D::Error>
+        where D: serde::Deserializer<'de>
+    {
+        let bytes: Vec<u8> = serde::Deserialize::deserialize(deserializer)?;
+        Bloom::from_bytes(&bytes).map_err(serde::de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate test;
 
     use self::test::{Bencher, black_box};
 
-    use super::Bloom;
+    use super::{Bloom, Mode};
     use super::hamcrest::*;
 
     #[test]
@@ -93,6 +273,89 @@ mod tests {
         assert_that(bloom.contains("b"), is(equal_to(false)));
     }
 
+    #[test]
+    fn filter_survives_a_round_trip_through_bytes() {
+        let mut bloom: Bloom<&'static str> = Bloom::new(10_000, 0.01);
+        bloom.add("a");
+        let decoded: Bloom<&'static str> = Bloom::from_bytes(&bloom.to_bytes()).unwrap();
+        assert_that(decoded.contains("a"), is(equal_to(true)));
+        assert_that(decoded.contains("b"), is(equal_to(false)));
+    }
+
+    #[test]
+    fn decoding_stops_at_a_truncated_buffer() {
+        let bloom: Bloom<&'static str> = Bloom::new(10_000, 0.01);
+        let mut bytes = bloom.to_bytes();
+        bytes.truncate(4);
+        assert_that(Bloom::<&'static str>::from_bytes(&bytes).is_err(), is(equal_to(true)));
+    }
+
+    #[test]
+    fn union_contains_keys_from_either_filter() {
+        let mut a: Bloom<&'static str> = Bloom::new(10_000, 0.01);
+        let mut b: Bloom<&'static str> = Bloom::new(10_000, 0.01);
+        a.add("a");
+        b.add("b");
+        a.union(&b);
+        assert_that(a.contains("a"), is(equal_to(true)));
+        assert_that(a.contains("b"), is(equal_to(true)));
+    }
+
+    #[test]
+    fn intersect_only_contains_keys_from_both_filters() {
+        let mut a: Bloom<&'static str> = Bloom::new(10_000, 0.01);
+        let mut b: Bloom<&'static str> = Bloom::new(10_000, 0.01);
+        a.add("a");
+        a.add("shared");
+        b.add("shared");
+        a.intersect(&b);
+        assert_that(a.contains("shared"), is(equal_to(true)));
+        assert_that(a.contains("a"), is(equal_to(false)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn union_panics_for_incompatible_filters() {
+        let mut a: Bloom<&'static str> = Bloom::new(10_000, 0.01);
+        let b: Bloom<&'static str> = Bloom::new(100, 0.01);
+        a.union(&b);
+    }
+
+    #[test]
+    #[should_panic]
+    fn union_panics_for_filters_with_a_different_mode_or_tweak() {
+        let mut a: Bloom<&'static str> = Bloom::with_mode(10_000, 0.01, Mode::DoubleHashing, 0);
+        let b: Bloom<&'static str> = Bloom::with_mode(10_000, 0.01, Mode::PerHashSeed, 42);
+        a.union(&b);
+    }
+
+    #[test]
+    fn per_hash_seed_mode_also_finds_added_values() {
+        let mut bloom: Bloom<&'static str> = Bloom::with_mode(10_000, 0.01, Mode::PerHashSeed, 42);
+        bloom.add("a");
+        assert_that(bloom.contains("a"), is(equal_to(true)));
+        assert_that(bloom.contains("b"), is(equal_to(false)));
+    }
+
+    #[test]
+    fn filter_with_a_non_default_mode_survives_a_round_trip_through_bytes() {
+        let mut bloom: Bloom<&'static str> = Bloom::with_mode(10_000, 0.01, Mode::PerHashSeed, 42);
+        bloom.add("a");
+        let decoded: Bloom<&'static str> = Bloom::from_bytes(&bloom.to_bytes()).unwrap();
+        assert_that(decoded.contains("a"), is(equal_to(true)));
+        assert_that(decoded.contains("b"), is(equal_to(false)));
+    }
+
+    #[test]
+    fn fill_ratio_tracks_the_fraction_of_bits_set() {
+        let mut bloom: Bloom<&'static str> = Bloom::new(10_000, 0.01);
+        assert_that(bloom.fill_ratio(), is(equal_to(0.0)));
+        bloom.add("a");
+        assert_that(bloom.fill_ratio() > 0.0, is(equal_to(true)));
+        assert_that(bloom.estimated_false_positive_rate() > 0.0, is(equal_to(true)));
+        assert_that(bloom.estimated_len() > 0.0, is(equal_to(true)));
+    }
+
     #[bench]
     fn creation_overhead(b: &mut Bencher) {
         b.iter(|| {
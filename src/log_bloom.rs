@@ -0,0 +1,90 @@
+use std::io::Cursor;
+
+use murmur3::murmur3_x64_128;
+use byteorder::{BigEndian, ByteOrder};
+
+const NUM_BYTES: usize = 256;
+
+/// Ethereum-style fixed-size log bloom: a 2048-bit (256-byte) filter with
+/// exactly 3 bits set per key. Unlike `Bloom`, there is no sizing math and
+/// no allocation, which makes it a good fit for the many-small-sets case
+/// where `Bloom::new`'s parameters would be overkill.
+pub struct LogBloom {
+    bits: [u8; NUM_BYTES],
+}
+
+impl LogBloom {
+    pub fn new() -> LogBloom {
+        LogBloom { bits: [0u8; NUM_BYTES] }
+    }
+
+    // Takes three pairs of bytes from the key's murmur3 digest; each pair is
+    // read as a big-endian u16 and masked to an index in 0..2048.
+    fn bit_indices<K: AsRef<[u8]>>(key: K) -> [usize; 3] {
+        let mut hash_result = [0u8; 16];
+        let mut key_reader = Cursor::new(key.as_ref());
+        murmur3_x64_128(&mut key_reader, 0, &mut hash_result);
+
+        let mut indices = [0usize; 3];
+        for i in 0..3 {
+            let pair = BigEndian::read_u16(&hash_result[i * 2..]);
+            indices[i] = (pair & 0x7FF) as usize;
+        }
+        indices
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bits[index / 8] |= 1 << (index % 8);
+    }
+
+    fn get_bit(&self, index: usize) -> bool {
+        self.bits[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    /// Folds `key` into the filter by setting its three bit positions.
+    pub fn shift_bloom<K: AsRef<[u8]>>(&mut self, key: K) {
+        for &index in Self::bit_indices(key).iter() {
+            self.set_bit(index);
+        }
+    }
+
+    /// Tests whether all three of `key`'s bit positions are set.
+    pub fn contains_bloom<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        Self::bit_indices(key).iter().all(|&index| self.get_bit(index))
+    }
+
+    /// Returns the raw 256-byte backing store, so filters can be OR-combined
+    /// cheaply byte-by-byte.
+    pub fn bloom_part(&self) -> &[u8; NUM_BYTES] {
+        &self.bits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogBloom;
+    use hamcrest::*;
+
+    #[test]
+    fn added_value_is_part_of_a_set() {
+        let mut bloom = LogBloom::new();
+        bloom.shift_bloom("a");
+        assert_that(bloom.contains_bloom("a"), is(equal_to(true)));
+        assert_that(bloom.contains_bloom("b"), is(equal_to(false)));
+    }
+
+    #[test]
+    fn bloom_part_can_be_or_combined_into_another_filter() {
+        let mut a = LogBloom::new();
+        let mut b = LogBloom::new();
+        a.shift_bloom("a");
+        b.shift_bloom("b");
+
+        let mut combined = LogBloom::new();
+        for i in 0..combined.bits.len() {
+            combined.bits[i] = a.bloom_part()[i] | b.bloom_part()[i];
+        }
+        assert_that(combined.contains_bloom("a"), is(equal_to(true)));
+        assert_that(combined.contains_bloom("b"), is(equal_to(true)));
+    }
+}
@@ -1,6 +1,7 @@
 use std::mem::size_of;
 use std::usize;
 use std::cmp::{max, min};
+use byteorder::{BigEndian, WriteBytesExt};
 
 pub struct PackedVec {
     num_elem: usize,
@@ -28,17 +29,21 @@ impl PackedVec {
         self.num_elem
     }
 
+    fn element_mask(&self) -> usize {
+        if self.element_size_bits == size_of::<usize>() * 8 {
+            usize::MAX
+        } else {
+            (1 << self.element_size_bits) - 1
+        }
+    }
+
     fn with_element<F>(&mut self, index: usize, mut f: F)
         where F: FnMut(usize) -> Option<usize>
     {
         let bucket_index = index / self.elements_per_bucket;
         let bucket = self.data[bucket_index];
         let element_index = index % self.elements_per_bucket;
-        let element_mask = if self.element_size_bits == size_of::<usize>() * 8 {
-            usize::MAX
-        } else {
-            (1 << self.element_size_bits) - 1
-        };
+        let element_mask = self.element_mask();
         let shift_size = self.bucket_size_bits - self.element_size_bits * (element_index + 1);
         let element = bucket >> shift_size & element_mask;
         match f(element) {
@@ -57,20 +62,30 @@ impl PackedVec {
         min(max(value, 0), max_value)
     }
 
-    pub fn increment(&mut self, index: usize) {
+    /// Increments the counter at `index`, returning `true` if it was zero
+    /// beforehand (i.e. this counter just became non-zero).
+    pub fn increment(&mut self, index: usize) -> bool {
+        let mut was_zero = false;
         self.with_element(index, |element| {
+            was_zero = element == 0;
             Some(element + 1)
         });
+        was_zero
     }
 
-    pub fn decrement(&mut self, index: usize) {
+    /// Decrements the counter at `index`, returning `true` if it just became
+    /// zero (i.e. this counter was non-zero beforehand).
+    pub fn decrement(&mut self, index: usize) -> bool {
+        let mut became_zero = false;
         self.with_element(index, |element| {
             if element == 0 {
                 Some(0)
             } else {
+                became_zero = element == 1;
                 Some(element - 1)
             }
         });
+        became_zero
     }
 
     pub fn get(&mut self, index: usize) -> Option<usize> {
@@ -82,12 +97,72 @@ impl PackedVec {
         found_element
     }
 
-    #[allow(dead_code)]
     fn set(&mut self, index: usize, value: usize) {
         self.with_element(index, |_element| {
             Some(value)
         });
     }
+
+    pub(crate) fn element_size_bits(&self) -> usize {
+        self.element_size_bits
+    }
+
+    /// Element-wise maxes `other` into `self`, keeping whichever counter at
+    /// each index is larger. Assumes both vectors have the same length and
+    /// `element_size_bits`.
+    pub(crate) fn merge_max(&mut self, other: &mut PackedVec) {
+        for i in 0..self.num_elem {
+            let ours = self.get(i).unwrap();
+            let theirs = other.get(i).unwrap();
+            if theirs > ours {
+                self.set(i, theirs);
+            }
+        }
+    }
+
+    /// Element-wise saturating-adds `other` into `self`, capping each
+    /// counter at the maximum value representable in `element_size_bits`.
+    /// Assumes both vectors have the same length and `element_size_bits`.
+    pub(crate) fn merge_saturating_add(&mut self, other: &mut PackedVec) {
+        let max_value = self.element_mask();
+        for i in 0..self.num_elem {
+            let ours = self.get(i).unwrap();
+            let theirs = other.get(i).unwrap();
+            self.set(i, min(ours.saturating_add(theirs), max_value));
+        }
+    }
+
+    pub(crate) fn expected_bucket_count(num_elem: usize, element_size_bits: usize) -> usize {
+        let bucket_size_bits = size_of::<usize>() * 8;
+        let elements_per_bucket = (bucket_size_bits as f64 / element_size_bits as f64).floor();
+        (num_elem as f64 / elements_per_bucket).ceil() as usize
+    }
+
+    // Buckets are written as big-endian u64s, but the packing itself (how
+    // many elements share a bucket) is still derived from the native `usize`
+    // width, so this wire format only round-trips between hosts with the
+    // same `usize` size. `from_raw_buckets` does not check this itself; a
+    // mismatch is caught by `CountingBloom::from_bytes`'s bucket count check
+    // only when it changes the number of buckets.
+    pub(crate) fn to_raw_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.data.len() * 8);
+        for bucket in &self.data {
+            buf.write_u64::<BigEndian>(*bucket as u64).unwrap();
+        }
+        buf
+    }
+
+    pub(crate) fn from_raw_buckets(num_elem: usize, element_size_bits: usize, data: Vec<usize>) -> PackedVec {
+        let bucket_size_bits = size_of::<usize>() * 8;
+        let elements_per_bucket = (bucket_size_bits as f64 / element_size_bits as f64).floor();
+        PackedVec {
+            num_elem: num_elem,
+            element_size_bits: element_size_bits,
+            bucket_size_bits: bucket_size_bits,
+            elements_per_bucket: elements_per_bucket as usize,
+            data: data,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -143,6 +218,29 @@ mod tests {
         assert_that(v.get(0), is(equal_to(Some(3))));
     }
 
+    #[test]
+    fn merging_with_max_keeps_the_larger_counter() {
+        let mut a = PackedVec::new(5, 2);
+        let mut b = PackedVec::new(5, 2);
+        a.set(0, 1);
+        b.set(0, 2);
+        a.merge_max(&mut b);
+        assert_that(a.get(0), is(equal_to(Some(2))));
+    }
+
+    #[test]
+    fn merging_with_saturating_add_sums_counters_and_caps_at_the_max_value() {
+        let mut a = PackedVec::new(5, 2);
+        let mut b = PackedVec::new(5, 2);
+        a.set(0, 1);
+        b.set(0, 1);
+        a.set(1, 2);
+        b.set(1, 3);
+        a.merge_saturating_add(&mut b);
+        assert_that(a.get(0), is(equal_to(Some(2))));
+        assert_that(a.get(1), is(equal_to(Some(3))));
+    }
+
     #[test]
     fn value_at_index_is_decremented() {
         let mut v = new_default();